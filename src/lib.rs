@@ -22,8 +22,15 @@
 /// we'll _never_ have any 1s in the continued fractions that we create.
 const FUDGE: u64 = 2;
 
+/// The matrix entries for a path grow roughly like the product of the (fudged) path elements seen
+/// so far, so they explode Fibonacci-style with depth.  We store them as `i128` instead of `u64`
+/// so that realistic trees have a lot more headroom before they overflow, and so that the signed
+/// intermediates needed to invert an element matrix (see `split_last` and `inverse`, and the
+/// ancestor/descendant operations built on top of them) don't need a separate representation.
+type Entry = i128;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct PathIdentifier(u64, u64, u64, u64);
+pub struct PathIdentifier(Entry, Entry, Entry, Entry);
 
 impl PathIdentifier {
     pub fn root() -> PathIdentifier {
@@ -35,7 +42,7 @@ impl PathIdentifier {
     }
 
     fn from_path_element(element: u64) -> PathIdentifier {
-        PathIdentifier(element, 1, 1, 0)
+        PathIdentifier(element as Entry, 1, 1, 0)
     }
 }
 
@@ -67,6 +74,33 @@ impl std::ops::MulAssign for PathIdentifier {
     }
 }
 
+impl PathIdentifier {
+    /// Like `*`, but detects overflow in the underlying matrix multiplication instead of silently
+    /// wrapping (which would make `path()` regenerate the wrong path).  Returns `None` if any of
+    /// the intermediate products or sums overflow `i128`.
+    ///
+    /// This is the primitive that [`try_child`](Self::try_child) and
+    /// [`try_push_child`](Self::try_push_child) build on to detect overflow when growing a path,
+    /// which is where it matters in practice. The navigation methods that shrink or recombine an
+    /// already-constructed identifier (`parent`, `pop`, `strip_prefix`, `common_ancestor`) assume
+    /// their inputs already fit in an `i128` and don't re-check; build identifiers with the
+    /// `try_*` methods if that assumption needs to be load-bearing for very deep trees.
+    pub fn checked_mul(&self, other: &PathIdentifier) -> Option<PathIdentifier> {
+        fn checked_dot(a0: Entry, a1: Entry, b0: Entry, b1: Entry) -> Option<Entry> {
+            let left = a0.checked_mul(b0)?;
+            let right = a1.checked_mul(b1)?;
+            left.checked_add(right)
+        }
+
+        Some(PathIdentifier(
+            checked_dot(self.0, self.1, other.0, other.2)?,
+            checked_dot(self.0, self.1, other.1, other.3)?,
+            checked_dot(self.2, self.3, other.0, other.2)?,
+            checked_dot(self.2, self.3, other.1, other.3)?,
+        ))
+    }
+}
+
 impl std::iter::FromIterator<u64> for PathIdentifier {
     fn from_iter<T>(iter: T) -> Self
     where
@@ -80,6 +114,22 @@ impl std::iter::FromIterator<u64> for PathIdentifier {
     }
 }
 
+impl PathIdentifier {
+    /// Like [`FromIterator::from_iter`], but returns `None` instead of overflowing if the path is
+    /// deep enough (or its elements large enough) that the underlying matrix entries would no
+    /// longer fit in an `i128`.
+    pub fn try_from_iter<T>(iter: T) -> Option<PathIdentifier>
+    where
+        T: IntoIterator<Item = u64>,
+    {
+        let mut id = PathIdentifier::root();
+        for piece in iter.into_iter() {
+            id = id.checked_mul(&PathIdentifier::from_path_element(piece + FUDGE))?;
+        }
+        Some(id)
+    }
+}
+
 impl std::str::FromStr for PathIdentifier {
     type Err = std::num::ParseIntError;
 
@@ -92,6 +142,22 @@ impl std::str::FromStr for PathIdentifier {
     }
 }
 
+/// Renders the same dotted, 0-based form that [`FromStr`][std::str::FromStr] parses, so that
+/// `s.parse::<PathIdentifier>().unwrap().to_string() == s` for any canonical input, including the
+/// empty string for `root()`.
+impl std::fmt::Display for PathIdentifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut elements = self.path();
+        if let Some(first) = elements.next() {
+            write!(f, "{}", first)?;
+            for element in elements {
+                write!(f, ".{}", element)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl From<Vec<u64>> for PathIdentifier {
     fn from(pieces: Vec<u64>) -> PathIdentifier {
         pieces.into_iter().collect()
@@ -100,7 +166,13 @@ impl From<Vec<u64>> for PathIdentifier {
 
 impl PartialEq<(u64, u64, u64, u64)> for PathIdentifier {
     fn eq(&self, other: &(u64, u64, u64, u64)) -> bool {
-        *self == PathIdentifier(other.0, other.1, other.2, other.3)
+        *self
+            == PathIdentifier(
+                other.0 as Entry,
+                other.1 as Entry,
+                other.2 as Entry,
+                other.3 as Entry,
+            )
     }
 }
 
@@ -133,7 +205,7 @@ impl Iterator for PathIterator {
         self.current.1 = s3;
         self.current.2 = s0 - s2 * result;
         self.current.3 = s1 - s3 * result;
-        Some(result)
+        Some(result as u64)
     }
 }
 
@@ -143,6 +215,237 @@ impl PathIdentifier {
     }
 }
 
+/// Orders identifiers in pre-order tree traversal order: an ancestor sorts before its descendants,
+/// and siblings sort by their 0-based index.  We compare the continued-fraction digit streams
+/// produced by `path_iter` element-by-element, rather than the raw matrix entries (which are not
+/// monotone in traversal order), and treat a path that runs out first as the lesser one, since a
+/// shorter prefix always comes before anything that extends it.
+impl Ord for PathIdentifier {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        use std::cmp::Ordering;
+
+        let mut ours = self.path_iter();
+        let mut theirs = other.path_iter();
+        loop {
+            return match (ours.next(), theirs.next()) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Less,
+                (Some(_), None) => Ordering::Greater,
+                (Some(a), Some(b)) => match a.cmp(&b) {
+                    Ordering::Equal => continue,
+                    ordering => ordering,
+                },
+            };
+        }
+    }
+}
+
+impl PartialOrd for PathIdentifier {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PathIdentifier {
+    /// The number of elements in this path.  `PathIdentifier::root()` has a depth of 0.
+    pub fn depth(&self) -> usize {
+        self.path_iter().count()
+    }
+
+    /// Returns the identifier of this path's final element, along with its parent's identifier.
+    ///
+    /// The last-pushed element's element matrix is always the rightmost factor of the product
+    /// that makes up `self`, so we find it by running the continued-fraction extraction all the
+    /// way to the end, and then undo it by right-multiplying by that element's (invertible, since
+    /// its determinant is always -1) matrix inverse.
+    fn split_last(&self) -> Option<(PathIdentifier, u64)> {
+        let last = self.path_iter().last()?;
+        let inverse = PathIdentifier(0, 1, 1, -(last as Entry));
+        Some((self.clone() * inverse, last))
+    }
+
+    /// Returns the identifier of this path's parent, or `None` if this is the root.
+    pub fn parent(&self) -> Option<PathIdentifier> {
+        self.split_last().map(|(parent, _)| parent)
+    }
+
+    /// Removes this path's final element, updating `self` to refer to its parent and returning
+    /// the (0-based) index of the element that was removed, or `None` if this is already the
+    /// root.
+    pub fn pop(&mut self) -> Option<u64> {
+        let (parent, last) = self.split_last()?;
+        *self = parent;
+        Some(last - FUDGE)
+    }
+
+    /// Returns the identifier of the `index`th child of this path.
+    ///
+    /// This is the main way fresh identifiers grow, and therefore the main place the overflow
+    /// that [`checked_mul`](Self::checked_mul) detects actually shows up in practice: for a
+    /// sufficiently deep tree or large indexes, this can silently wrap instead of panicking (in a
+    /// release build) or panic (in a debug build). Use [`try_child`](Self::try_child) if you need
+    /// to detect that instead.
+    pub fn child(&self, index: u64) -> PathIdentifier {
+        self.clone() * PathIdentifier::from_path_element(index + FUDGE)
+    }
+
+    /// Like [`child`](Self::child), but returns `None` instead of overflowing if the resulting
+    /// path is too deep (or its indexes too large) for the underlying matrix entries to fit in an
+    /// `i128`.
+    pub fn try_child(&self, index: u64) -> Option<PathIdentifier> {
+        self.checked_mul(&PathIdentifier::from_path_element(index + FUDGE))
+    }
+
+    /// Appends a new element to this path, turning it into the identifier of its `index`th child.
+    ///
+    /// See the overflow caveat on [`child`](Self::child); use
+    /// [`try_push_child`](Self::try_push_child) if you need to detect it instead.
+    pub fn push_child(&mut self, index: u64) {
+        *self *= PathIdentifier::from_path_element(index + FUDGE);
+    }
+
+    /// Like [`push_child`](Self::push_child), but returns `None` instead of overflowing if the
+    /// resulting path would be too deep (or its indexes too large) for the underlying matrix
+    /// entries to fit in an `i128`. Leaves `self` unchanged on overflow.
+    pub fn try_push_child(&mut self, index: u64) -> Option<()> {
+        *self = self.checked_mul(&PathIdentifier::from_path_element(index + FUDGE))?;
+        Some(())
+    }
+}
+
+impl PathIdentifier {
+    /// The determinant of this identifier's matrix representation.  Every element matrix has
+    /// determinant -1, so a depth-n path's matrix always has determinant `(-1)^n`.
+    fn determinant(&self) -> Entry {
+        self.0 * self.3 - self.1 * self.2
+    }
+
+    /// The matrix inverse of this identifier.  Since the determinant is always ±1, the inverse's
+    /// entries are themselves integers, so it fits in the same representation as `self`.
+    fn inverse(&self) -> PathIdentifier {
+        let det = self.determinant();
+        PathIdentifier(det * self.3, -det * self.1, -det * self.2, det * self.0)
+    }
+
+    /// Whether this identifier's matrix could actually have been produced by multiplying together
+    /// a sequence of real (fudged, 0-based) element matrices.  `strip_prefix` can produce matrices
+    /// that don't have this shape, e.g. if the supposed ancestor isn't really an ancestor; we
+    /// check by running the same continued-fraction extraction that `path_iter` uses, but bailing
+    /// out instead of dividing by zero or looping forever if we see something that couldn't have
+    /// come from a real path element.
+    fn is_valid_path(&self) -> bool {
+        let root = PathIdentifier::root();
+        let mut current = self.clone();
+        while current != root {
+            if current.2 <= 0 {
+                return false;
+            }
+            let quotient = current.0 / current.2;
+            if quotient < FUDGE as Entry {
+                return false;
+            }
+            let s0 = current.0;
+            let s1 = current.1;
+            let s2 = current.2;
+            let s3 = current.3;
+            current.0 = s2;
+            current.1 = s3;
+            current.2 = s0 - s2 * quotient;
+            current.3 = s1 - s3 * quotient;
+        }
+        true
+    }
+
+    /// If `self` is `ancestor`, or a descendant of it, returns the relative path that leads from
+    /// `ancestor` down to `self`.  Returns `None` if `self` is not a descendant of `ancestor`.
+    ///
+    /// Every `PathIdentifier` is a product of determinant-(-1) element matrices, so the whole
+    /// matrix is invertible over the integers; the relative path is `ancestor^-1 * self`, using
+    /// signed intermediate arithmetic.
+    pub fn strip_prefix(&self, ancestor: &PathIdentifier) -> Option<PathIdentifier> {
+        let suffix = ancestor.inverse() * self.clone();
+        if suffix.is_valid_path() {
+            Some(suffix)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `self` is an ancestor of (or equal to) `other`.
+    pub fn is_ancestor_of(&self, other: &PathIdentifier) -> bool {
+        other.strip_prefix(self).is_some()
+    }
+
+    /// Returns the deepest node that is an ancestor of both `self` and `other`.
+    ///
+    /// We drive the continued-fraction extraction (the same Euclidean step that `PathIterator`
+    /// uses) on both identifiers in lockstep, accumulating each shared leading element onto a
+    /// running product, and stop at the first element where they diverge (or either path runs
+    /// out).  If the two paths are identical, the accumulated product is a clone of either; if one
+    /// is a prefix of the other, it's the shorter one; if they diverge at the first element, it's
+    /// `root()`.
+    pub fn common_ancestor(&self, other: &PathIdentifier) -> PathIdentifier {
+        let mut ours = self.path_iter();
+        let mut theirs = other.path_iter();
+        let mut ancestor = PathIdentifier::root();
+        while let (Some(a), Some(b)) = (ours.next(), theirs.next()) {
+            if a != b {
+                break;
+            }
+            ancestor *= PathIdentifier::from_path_element(a);
+        }
+        ancestor
+    }
+}
+
+/// By default, a `PathIdentifier` serializes as its compact dotted string form (the same form
+/// that [`Display`][std::fmt::Display] produces and [`FromStr`][std::str::FromStr] parses), since
+/// that's the stable, human-readable form most callers want as a map key or wire identifier.
+#[cfg(feature = "serde")]
+impl serde::Serialize for PathIdentifier {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for PathIdentifier {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = <&str>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// An alternate serde representation that stores a [`PathIdentifier`]'s four raw matrix entries
+/// directly, for callers who already have the identifier and don't want to pay the cost of
+/// re-expanding it into a path and back.  Opt in on a field with `#[serde(with = "rational_trees::matrix")]`.
+#[cfg(feature = "serde")]
+pub mod matrix {
+    use super::{Entry, PathIdentifier};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(id: &PathIdentifier, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        (id.0, id.1, id.2, id.3).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<PathIdentifier, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let (a, b, c, d) = <(Entry, Entry, Entry, Entry)>::deserialize(deserializer)?;
+        Ok(PathIdentifier(a, b, c, d))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -183,11 +486,215 @@ mod tests {
 
     #[test]
     fn can_generate_paths() {
-        assert_eq!(generate_path(""), vec![]);
+        // Pin the element type explicitly: once the `serde` feature (and `serde_json` as its
+        // dev-dependency) is enabled, `serde_json::Value`'s blanket `PartialEq` impls make an
+        // untyped empty `vec![]` here ambiguous between `u64` and `Value`.
+        assert_eq!(generate_path(""), Vec::<u64>::new());
         assert_eq!(generate_path("3"), vec![3]);
         assert_eq!(generate_path("3.12"), vec![3, 12]);
         assert_eq!(generate_path("3.12.5"), vec![3, 12, 5]);
         assert_eq!(generate_path("3.12.5.1"), vec![3, 12, 5, 1]);
         assert_eq!(generate_path("3.12.5.1.21"), vec![3, 12, 5, 1, 21]);
     }
+
+    #[test]
+    fn can_compute_depth() {
+        assert_eq!(parse_id("").depth(), 0);
+        assert_eq!(parse_id("3").depth(), 1);
+        assert_eq!(parse_id("3.12.5.1.21").depth(), 5);
+    }
+
+    #[test]
+    fn can_walk_to_parent() {
+        assert_eq!(parse_id("").parent(), None);
+        assert_eq!(parse_id("3").parent(), Some(parse_id("")));
+        assert_eq!(parse_id("3.12.5").parent(), Some(parse_id("3.12")));
+    }
+
+    #[test]
+    fn can_pop_elements() {
+        let mut id = parse_id("3.12.5");
+        assert_eq!(id.pop(), Some(5));
+        assert_eq!(id, parse_id("3.12"));
+        assert_eq!(id.pop(), Some(12));
+        assert_eq!(id, parse_id("3"));
+        assert_eq!(id.pop(), Some(3));
+        assert_eq!(id, parse_id(""));
+        assert_eq!(id.pop(), None);
+    }
+
+    #[test]
+    fn can_push_and_index_children() {
+        let mut id = parse_id("3.12");
+        assert_eq!(id.child(5), parse_id("3.12.5"));
+        id.push_child(5);
+        assert_eq!(id, parse_id("3.12.5"));
+    }
+
+    #[test]
+    fn try_push_and_index_children_detect_overflow() {
+        let mut id = parse_id("3.12");
+        assert_eq!(id.try_child(5), Some(parse_id("3.12.5")));
+        assert!(id.try_push_child(5).is_some());
+        assert_eq!(id, parse_id("3.12.5"));
+
+        let huge = PathIdentifier(Entry::MAX, 1, 1, 0);
+        assert_eq!(huge.try_child(2), None);
+        let mut huge = huge;
+        assert_eq!(huge.try_push_child(2), None);
+        assert_eq!(huge, PathIdentifier(Entry::MAX, 1, 1, 0));
+    }
+
+    #[test]
+    fn can_strip_prefix() {
+        assert_eq!(
+            parse_id("3.12.5").strip_prefix(&parse_id("3.12")),
+            Some(parse_id("5"))
+        );
+        assert_eq!(
+            parse_id("3.12.5.1.21").strip_prefix(&parse_id("3")),
+            Some(parse_id("12.5.1.21"))
+        );
+        assert_eq!(
+            parse_id("3.12").strip_prefix(&parse_id("3.12")),
+            Some(parse_id(""))
+        );
+        assert_eq!(
+            parse_id("3.12").strip_prefix(&parse_id("")),
+            Some(parse_id("3.12"))
+        );
+    }
+
+    #[test]
+    fn strip_prefix_rejects_non_ancestors() {
+        assert_eq!(parse_id("3.12").strip_prefix(&parse_id("3.5")), None);
+        assert_eq!(parse_id("3").strip_prefix(&parse_id("3.12")), None);
+        assert_eq!(parse_id("3").strip_prefix(&parse_id("12")), None);
+    }
+
+    #[test]
+    fn can_check_is_ancestor_of() {
+        assert!(parse_id("3.12").is_ancestor_of(&parse_id("3.12.5")));
+        assert!(parse_id("").is_ancestor_of(&parse_id("3.12.5")));
+        assert!(parse_id("3.12").is_ancestor_of(&parse_id("3.12")));
+        assert!(!parse_id("3.12.5").is_ancestor_of(&parse_id("3.12")));
+        assert!(!parse_id("3.5").is_ancestor_of(&parse_id("3.12")));
+    }
+
+    #[test]
+    fn common_ancestor_of_identical_paths_is_itself() {
+        assert_eq!(
+            parse_id("3.12.5").common_ancestor(&parse_id("3.12.5")),
+            parse_id("3.12.5")
+        );
+    }
+
+    #[test]
+    fn common_ancestor_of_prefix_is_the_shorter_path() {
+        assert_eq!(
+            parse_id("3.12").common_ancestor(&parse_id("3.12.5")),
+            parse_id("3.12")
+        );
+        assert_eq!(
+            parse_id("3.12.5").common_ancestor(&parse_id("3.12")),
+            parse_id("3.12")
+        );
+    }
+
+    #[test]
+    fn common_ancestor_of_diverging_paths_is_the_shared_prefix() {
+        assert_eq!(
+            parse_id("3.12.5").common_ancestor(&parse_id("3.12.9")),
+            parse_id("3.12")
+        );
+    }
+
+    #[test]
+    fn common_ancestor_of_disjoint_paths_is_root() {
+        assert_eq!(
+            parse_id("3.12.5").common_ancestor(&parse_id("9.1")),
+            parse_id("")
+        );
+    }
+
+    #[test]
+    fn orders_ancestors_before_descendants() {
+        assert!(parse_id("") < parse_id("3"));
+        assert!(parse_id("3") < parse_id("3.12"));
+        assert!(parse_id("3.12") < parse_id("3.12.5"));
+    }
+
+    #[test]
+    fn orders_siblings_by_index() {
+        assert!(parse_id("3.5") < parse_id("3.12"));
+        assert!(parse_id("3.12.0") < parse_id("3.12.1"));
+    }
+
+    #[test]
+    fn can_sort_a_whole_subtree() {
+        let mut ids: Vec<PathIdentifier> = vec!["3.12.5", "3", "3.12", "3.0", "", "3.9"]
+            .into_iter()
+            .map(parse_id)
+            .collect();
+        ids.sort();
+        let expected: Vec<PathIdentifier> =
+            vec!["", "3", "3.0", "3.9", "3.12", "3.12.5"]
+                .into_iter()
+                .map(parse_id)
+                .collect();
+        assert_eq!(ids, expected);
+    }
+
+    #[test]
+    fn can_round_trip_through_display() {
+        for s in &["", "3", "3.12", "3.12.5", "3.12.5.1", "3.12.5.1.21"] {
+            assert_eq!(parse_id(s).to_string(), *s);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn can_round_trip_through_serde_string() {
+        for s in &["", "3", "3.12.5"] {
+            let id = parse_id(s);
+            let json = serde_json::to_string(&id).unwrap();
+            assert_eq!(json, format!("\"{}\"", s));
+            assert_eq!(serde_json::from_str::<PathIdentifier>(&json).unwrap(), id);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn can_round_trip_through_serde_matrix() {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Wrapper(#[serde(with = "matrix")] PathIdentifier);
+
+        let id = parse_id("3.12.5");
+        let json = serde_json::to_string(&Wrapper(id.clone())).unwrap();
+        let Wrapper(round_tripped) = serde_json::from_str(&json).unwrap();
+        assert_eq!(round_tripped, id);
+    }
+
+    #[test]
+    fn checked_mul_matches_mul_when_no_overflow() {
+        let a = parse_id("3.12");
+        let b = PathIdentifier::from_path_element(5 + FUDGE);
+        assert_eq!(a.clone().checked_mul(&b), Some(a * b));
+    }
+
+    #[test]
+    fn checked_mul_detects_overflow() {
+        let huge = PathIdentifier(Entry::MAX, 1, 1, 0);
+        let other = PathIdentifier::from_path_element(2);
+        assert_eq!(huge.checked_mul(&other), None);
+    }
+
+    #[test]
+    fn try_from_iter_detects_overflow() {
+        // Every element (even after adding FUDGE) fits in a u64, but the matrix entries grow like
+        // the product of the elements, so with elements this large they no longer fit in an i128
+        // by the third element.
+        let elements = std::iter::repeat_n(u64::MAX / 4, 10);
+        assert_eq!(PathIdentifier::try_from_iter(elements), None);
+    }
 }